@@ -2,10 +2,16 @@
 
 import std::arc::{ARC, get, clone};
 
+import std::map::{HashMap, hashmap};
+import std::sort::merge_sort;
+
 import css::values::{DisplayType, DisplayNone, Inline, Block, Unit, Auto};
+import css::values::{Px, Em, Pt, Percent};
 import css::values::Stylesheet;
+import css::values::{Selector, Element as SelElement, Child, Descendant, Sibling};
+import css::values::{Attr as SelAttr, Class, Id};
 import dom::base::{HTMLDivElement, HTMLHeadElement, HTMLImageElement, UnknownElement, HTMLScriptElement};
-import dom::base::{Comment, Doctype, Element, Node, NodeKind, Text};
+import dom::base::{Attr, Comment, Doctype, Element, Node, NodeKind, Text};
 import util::color::{Color, rgb};
 import util::color::css_colors::{white, black};
 import layout::base::{LayoutData, NTree};
@@ -18,6 +24,34 @@ type SpecifiedStyle = {mut background_color : Option<Color>,
                         mut width : Option<Unit>
                        };
 
+/**
+ * A bitflag, stored in `LayoutData`, recording why a node needs restyling.
+ * `RESTYLE_SELF` means the node's own matched style may have changed;
+ * `RESTYLE_DESCENDANTS` means its whole subtree must be revisited (e.g. a rule
+ * that could match descendants was added). A node whose hint is empty, and
+ * whose parent did not change an inherited property, can be skipped entirely.
+ */
+type RestyleHint = u8;
+
+const RESTYLE_NONE : u8 = 0u8;
+const RESTYLE_SELF : u8 = 1u8;
+const RESTYLE_DESCENDANTS : u8 = 2u8;
+// A summary bit, propagated up to the ancestors of a dirtied node, meaning
+// "some node in this subtree is dirty." It lets `flatten_dirty` descend toward
+// the dirt while pruning the clean subtrees on either side.
+const RESTYLE_DIRTY_DESCENDANT : u8 = 4u8;
+
+/**
+ * Records, per inherited property, whether the node's value came from an
+ * explicit selector match (`true`) or was inherited from its parent (`false`).
+ * `cascade_inherited` may freely overwrite the inherited ones on a later
+ * restyle, but must never clobber an explicitly matched value.
+ */
+type InheritedFlags = {
+    mut text_color : bool,
+    mut font_size : bool
+};
+
 trait DefaultStyleMethods {
     fn default_color() -> Color;
     fn default_display_type() -> DisplayType;
@@ -78,8 +112,325 @@ fn empty_style_for_node_kind(kind: NodeKind) -> SpecifiedStyle {
      mut width : None}
 }
 
+/**
+ * An indexed view of a `Stylesheet`. Rather than test every rule against every
+ * node, the rules are bucketed by the rightmost simple selector: by tag name,
+ * by class and by id, with a catch-all universal bucket for rules whose
+ * rightmost selector matches anything. Matching a node then only needs to test
+ * the rules in the buckets the node can fall into.
+ *
+ * Buckets store rule indices into the shared `Stylesheet`, so the `Stylist`
+ * can be cloned cheaply (it is the `ARC` that is shared) across the tasks that
+ * style a subtree.
+ *
+ * The buckets are plain immutable vectors — a name is paired with its rule
+ * indices and the name-keyed vectors are kept sorted — rather than
+ * `std::map::HashMap`s: `HashMap` is managed and non-`Const`, and a `mut` field
+ * is not freezable, so a `Stylist` built on them could not satisfy the
+ * `Const Send` bound `ARC` requires. The transient maps used while building are
+ * confined to `Stylist::new`.
+ */
+type RuleBucket = (~str, ~[uint]);
+
+type Stylist = {
+    sheet : ARC<Stylesheet>,
+    tag_rules : ~[RuleBucket],
+    class_rules : ~[RuleBucket],
+    id_rules : ~[RuleBucket],
+    universal_rules : ~[uint],
+    has_sibling_rules : bool
+};
+
+/// The bucket a rule is filed under, derived from its rightmost simple selector.
+enum RuleKey {
+    KeyTag(~str),
+    KeyClass(~str),
+    KeyId(~str),
+    KeyUniversal
+}
+
+/// Compute the bucket key for a single selector. The rightmost simple selector
+/// of a compound selector is the one that decides the bucket, and among the
+/// parts of that simple selector an id wins over a class, which wins over a tag.
+pure fn rightmost_key(selector: &Selector) -> RuleKey {
+    match *selector {
+      SelElement(tag, attrs) => {
+        let mut key = if tag == ~"*" { KeyUniversal } else { KeyTag(copy tag) };
+        for attrs.each |attr| {
+            match *attr {
+              Id(name) => { key = KeyId(copy name); }
+              Class(name) => {
+                // Only promote to a class key if we have not already found an id.
+                match key { KeyId(*) => (), _ => key = KeyClass(copy name) }
+              }
+              _ => ()
+            }
+        }
+        key
+      }
+      Child(_, rhs) | Descendant(_, rhs) | Sibling(_, rhs) => rightmost_key(rhs)
+    }
+}
+
+/// Whether a selector contains a `Sibling` combinator anywhere in its chain.
+/// Sibling selectors (`a + .foo`) make a node's match depend on its preceding
+/// siblings, which the `SharingKey` does not capture, so their presence
+/// disables style sharing entirely.
+pure fn selector_has_sibling(selector: &Selector) -> bool {
+    match *selector {
+      SelElement(*) => false,
+      Sibling(*) => true,
+      Child(lhs, rhs) | Descendant(lhs, rhs) =>
+        selector_has_sibling(lhs) || selector_has_sibling(rhs)
+    }
+}
+
+/// Append a rule index to the bucket named `key` in a transient build map.
+fn file_rule(map: HashMap<~str, ~[uint]>, key: ~str, index: uint) {
+    match map.find(copy key) {
+      Some(existing) => { map.insert(key, existing + ~[index]); }
+      None => { map.insert(key, ~[index]); }
+    }
+}
+
+/// Freeze a transient build map into an immutable, name-sorted bucket vector.
+fn freeze_buckets(map: HashMap<~str, ~[uint]>) -> ~[RuleBucket] {
+    let mut buckets = ~[];
+    for map.each |key, indices| { buckets += ~[(copy key, copy indices)]; }
+    merge_sort(|a, b| {
+        let (ka, _) = copy *a;
+        let (kb, _) = copy *b;
+        ka <= kb
+    }, buckets)
+}
+
+/// Look up a bucket by name, returning its rule indices or an empty vector.
+fn lookup_bucket(buckets: &~[RuleBucket], name: ~str) -> ~[uint] {
+    for buckets.each |bucket| {
+        let (key, indices) = copy *bucket;
+        if key == name { return indices; }
+    }
+    ~[]
+}
+
+impl Stylist {
+    /// Build a `Stylist` by bucketing every rule in the stylesheet under the
+    /// key of each of its selectors. The buckets are accumulated in transient
+    /// maps and then frozen into immutable vectors so the result is `Const`.
+    static fn new(sheet: ARC<Stylesheet>) -> Stylist {
+        let tag_map = HashMap();
+        let class_map = HashMap();
+        let id_map = HashMap();
+        let mut universal = ~[];
+        let mut has_sibling_rules = false;
+
+        let rules = get(&sheet);
+        let mut index = 0u;
+        while index < rules.len() {
+            let (selectors, _) = copy *rules[index];
+            for selectors.each |selector| {
+                if selector_has_sibling(selector) { has_sibling_rules = true; }
+                match rightmost_key(selector) {
+                  KeyTag(tag) => file_rule(tag_map, tag, index),
+                  KeyClass(name) => file_rule(class_map, name, index),
+                  KeyId(name) => file_rule(id_map, name, index),
+                  KeyUniversal => universal += ~[index]
+                }
+            }
+            index += 1u;
+        }
+
+        {
+            sheet : clone(&sheet),
+            tag_rules : freeze_buckets(tag_map),
+            class_rules : freeze_buckets(class_map),
+            id_rules : freeze_buckets(id_map),
+            universal_rules : universal,
+            has_sibling_rules : has_sibling_rules
+        }
+    }
+
+    /// Return the rules that could match an element with the given tag, class
+    /// list and optional id: the union of the matching buckets and the
+    /// universal bucket, materialized as a small candidate `Stylesheet`.
+    fn candidate_rules(tag: ~str, classes: ~[~str], id: Option<~str>) -> Stylesheet {
+        let mut indices = copy self.universal_rules;
+
+        indices += lookup_bucket(&self.tag_rules, tag);
+        for classes.each |class| {
+            indices += lookup_bucket(&self.class_rules, copy *class);
+        }
+        match id {
+          Some(name) => indices += lookup_bucket(&self.id_rules, name),
+          None => ()
+        }
+
+        // The buckets were gathered out of source order (universal, then tag,
+        // then the node's classes, then id), which would silently reorder
+        // equal-specificity rules under the last-wins cascade. Sort the indices
+        // ascending and drop the duplicates a rule picks up when more than one
+        // of its selectors lands in a queried bucket, so matching sees the
+        // rules in stylesheet source order exactly as the baseline did.
+        let sorted = merge_sort(|a, b| *a <= *b, indices);
+
+        let sheet = get(&self.sheet);
+        let mut candidates = ~[];
+        let mut last = None;
+        for sorted.each |index| {
+            match last {
+              Some(previous) if previous == *index => (),
+              _ => {
+                candidates += ~[copy *sheet[*index]];
+                last = Some(*index);
+              }
+            }
+        }
+        candidates
+    }
+}
+
+/**
+ * A cheap fingerprint used to decide whether two elements are likely to match
+ * the same set of selectors. Two nodes may share a `SpecifiedStyle` only when
+ * their keys are equal, and the key folds in the identity (`parent`) of the
+ * node's parent layout data: since `matched_rules` still hands combinator rules
+ * (`div > p`, `.a p`, sibling) to `match_css_style`, elements with the same
+ * tag and classes but different ancestors must not share. Keying on the parent
+ * restricts sharing to siblings, which share the same ancestor match context.
+ *
+ * Siblings do NOT share a sibling-combinator context, though: `a + .foo` matches
+ * only the `.foo` that follows an `a`. The key does not capture preceding
+ * siblings, so sharing is disabled wholesale (see `Stylist::has_sibling_rules`)
+ * whenever the stylesheet contains any sibling selector.
+ *
+ * Nodes carrying an `id` attribute or an inline style are never given a key
+ * (they get `None`) and so never participate in sharing.
+ */
+type SharingKey = {
+    element_kind : uint,
+    classes : ~[~str],
+    parent : uint
+};
+
+/**
+ * A small bounded, least-recently-used cache of recently matched nodes, held
+ * thread-locally while a worker styles a run of nodes. Each entry pairs a
+ * `SharingKey` with a cloned `SpecifiedStyle`; on a hit we copy the style
+ * rather than walking the stylesheet again. This is the style-sharing
+ * optimization real engines use to exploit pages built out of many
+ * structurally-identical rows or list items.
+ */
+type StyleSharingCache = {
+    mut entries : ~[{key : SharingKey, style : SpecifiedStyle}],
+    capacity : uint
+};
+
+/// A node queued for matching, paired with the identity of its parent's layout
+/// data so a worker can build the node's `SharingKey` without walking the tree.
+type StyleWorkItem = {
+    node : Node,
+    parent_key : uint
+};
+
+/// Create an empty sharing cache holding at most `capacity` entries. The cache
+/// is boxed in an `@` so a worker can thread one shared instance across the
+/// whole chunk it styles and have inserts persist from node to node.
+fn empty_style_sharing_cache(capacity : uint) -> @StyleSharingCache {
+    @{mut entries : ~[], capacity : capacity}
+}
+
+/// Two sharing keys are equal when they name the same element kind, carry the
+/// same ordered class list, and hang off the same parent.
+pure fn sharing_keys_equal(a : &SharingKey, b : &SharingKey) -> bool {
+    if a.parent != b.parent { return false; }
+    if a.element_kind != b.element_kind { return false; }
+    if a.classes.len() != b.classes.len() { return false; }
+    let mut i = 0u;
+    while i < a.classes.len() {
+        if a.classes[i] != b.classes[i] { return false; }
+        i += 1u;
+    }
+    return true;
+}
+
+impl StyleSharingCache {
+    /// Look for an entry whose key matches `key`, promoting it to the front
+    /// (most-recently-used) and returning a copy of its style on a hit.
+    fn find(key : &SharingKey) -> Option<SpecifiedStyle> {
+        let mut i = 0u;
+        while i < self.entries.len() {
+            if sharing_keys_equal(&self.entries[i].key, key) {
+                let hit = self.entries[i];
+                // Move the hit entry to the front to keep the list in LRU order.
+                let mut reordered = ~[hit];
+                let mut j = 0u;
+                while j < self.entries.len() {
+                    if j != i { reordered += ~[self.entries[j]]; }
+                    j += 1u;
+                }
+                self.entries = reordered;
+                return Some(copy hit.style);
+            }
+            i += 1u;
+        }
+        return None;
+    }
+
+    /// Insert a freshly matched style at the front, evicting the
+    /// least-recently-used entry when the cache is full.
+    fn insert(key : SharingKey, style : SpecifiedStyle) {
+        let mut reordered = ~[{key : key, style : copy style}];
+        let mut j = 0u;
+        while j < self.entries.len() && reordered.len() < self.capacity {
+            reordered += ~[self.entries[j]];
+            j += 1u;
+        }
+        self.entries = reordered;
+    }
+}
+
+/// The discriminant of an element kind, used as the tag component of a
+/// `SharingKey`. Two elements can only share a style if they are the same kind.
+pure fn element_kind_discriminant(element: &Element) -> uint {
+    match *element.kind {
+      HTMLDivElement    => 0u,
+      HTMLHeadElement   => 1u,
+      HTMLImageElement(*) => 2u,
+      HTMLScriptElement => 3u,
+      UnknownElement    => 4u
+    }
+}
+
+/// The lowercase tag name of an element, used to look up its tag bucket in the
+/// `Stylist`. Unknown elements have no meaningful tag name and so fall into no
+/// tag bucket; they still match the universal bucket and their class and id
+/// buckets.
+pure fn element_tag_name(element: &Element) -> ~str {
+    match *element.kind {
+      HTMLDivElement    => ~"div",
+      HTMLHeadElement   => ~"head",
+      HTMLImageElement(*) => ~"img",
+      HTMLScriptElement => ~"script",
+      UnknownElement    => ~""
+    }
+}
+
 trait StylePriv {
     fn initialize_style() -> ~[@LayoutData];
+    fn compute_sharing_key(parent_key: uint) -> Option<SharingKey>;
+    fn matched_rules(stylist: ARC<Stylist>) -> Stylesheet;
+    fn share_specified_style(style: SpecifiedStyle);
+    fn match_css_style_shared(stylesheet: Stylesheet, parent_key: uint,
+                              allow_sharing: bool, cache: @StyleSharingCache);
+    fn record_inherited_origin();
+    fn cascade_inherited(parent: &SpecifiedStyle);
+    fn restyle_hint() -> RestyleHint;
+    fn set_restyle_hint(hint: RestyleHint);
+    fn clear_restyle_hint();
+    fn tree_parent() -> Option<Node>;
+    fn has_dirty_ancestor(dirty: HashMap<uint, ()>) -> bool;
+    fn flatten_dirty(force: bool, parent_key: uint) -> ~[StyleWorkItem];
+    fn cascade_inherited_subtree(parent: Option<SpecifiedStyle>);
 }
 
 impl Node : StylePriv {
@@ -96,24 +447,282 @@ impl Node : StylePriv {
     fn initialize_style() -> ~[@LayoutData] {
         if !self.has_aux() {
             let node_kind = self.read(|n| copy *n.kind);
+            // A fresh node is born fully dirty so the first restyle matches it
+            // and its whole subtree; later restyles clear the hint and only set
+            // it again when something actually changes.
             let the_layout_data = @LayoutData({
                 mut specified_style : ~empty_style_for_node_kind(node_kind),
+                mut restyle_hint : RESTYLE_SELF | RESTYLE_DESCENDANTS,
+                mut inherited_explicit : {mut text_color : false, mut font_size : false},
                 mut box : None
             });
 
             self.set_aux(the_layout_data);
 
+            // Summarize the new dirt upward, as `mark_dirty` does, so that
+            // `flatten_dirty` reaches this node even when it was added under an
+            // already-styled parent.
+            let mut ancestor = self.tree_parent();
+            loop {
+                match ancestor {
+                  Some(node) if node.has_aux() => {
+                    if (node.restyle_hint() & RESTYLE_DIRTY_DESCENDANT) != RESTYLE_NONE {
+                        break;
+                    }
+                    node.set_restyle_hint(RESTYLE_DIRTY_DESCENDANT);
+                    ancestor = node.tree_parent();
+                  }
+                  _ => break
+                }
+            }
+
             ~[the_layout_data]
         } else {
             ~[]
         }
     }
+
+    #[doc="
+        Compute the style-sharing key for this node, or `None` if it must not be
+        shared. Only elements are sharable, and never those carrying an `id`
+        attribute or an inline `style` attribute.
+    "]
+    fn compute_sharing_key(parent_key: uint) -> Option<SharingKey> {
+        do self.read |n| {
+            match *n.kind {
+              Element(element) => {
+                let mut classes = ~[];
+                let mut unsharable = false;
+                for element.attrs.each |attr| {
+                    if attr.name == ~"id" || attr.name == ~"style" {
+                        unsharable = true;
+                    } else if attr.name == ~"class" {
+                        for str::split_char(attr.value, ' ').each |piece| {
+                            if piece.len() > 0u { classes += ~[copy piece]; }
+                        }
+                    }
+                }
+
+                if unsharable {
+                    None
+                } else {
+                    Some({element_kind : element_kind_discriminant(&element),
+                          classes : classes,
+                          parent : parent_key})
+                }
+              }
+              _ => None
+            }
+        }
+    }
+
+    #[doc="
+        Consult the `Stylist` for the rules that could match this node, based on
+        its tag, classes and id. Non-elements match only the universal bucket.
+    "]
+    fn matched_rules(stylist: ARC<Stylist>) -> Stylesheet {
+        do self.read |n| {
+            match *n.kind {
+              Element(element) => {
+                let mut classes = ~[];
+                let mut id = None;
+                for element.attrs.each |attr| {
+                    if attr.name == ~"id" {
+                        id = Some(copy attr.value);
+                    } else if attr.name == ~"class" {
+                        for str::split_char(attr.value, ' ').each |piece| {
+                            if piece.len() > 0u { classes += ~[copy piece]; }
+                        }
+                    }
+                }
+                get(&stylist).candidate_rules(element_tag_name(&element), classes, id)
+              }
+              _ => get(&stylist).candidate_rules(~"", ~[], None)
+            }
+        }
+    }
+
+    #[doc="Overwrite this node's specified style with a shared copy."]
+    fn share_specified_style(style: SpecifiedStyle) {
+        let layout_data = self.aux(|x| copy x);
+        *layout_data.specified_style = copy style;
+    }
+
+    #[doc="
+        Match this node's style, consulting the style-sharing cache first. On a
+        hit we copy the cached `SpecifiedStyle` rather than re-running selector
+        matching; on a miss we match normally and push the result into the
+        cache. When `allow_sharing` is false (the stylesheet has sibling
+        selectors) we bypass the cache and always match.
+    "]
+    fn match_css_style_shared(stylesheet: Stylesheet, parent_key: uint,
+                              allow_sharing: bool, cache: @StyleSharingCache) {
+        let key = if allow_sharing {
+            self.compute_sharing_key(parent_key)
+        } else {
+            None
+        };
+
+        match key {
+          Some(key) => {
+            match cache.find(&key) {
+              Some(shared) => self.share_specified_style(shared),
+              None => {
+                self.match_css_style(stylesheet);
+                cache.insert(key, self.get_specified_style());
+              }
+            }
+          }
+          None => self.match_css_style(stylesheet)
+        }
+
+        // Remember which inherited properties the match actually set, so the
+        // cascade can tell an explicit value apart from an inherited one. This
+        // runs before inheritance fills the gaps, while an unset inherited
+        // property is still `None`.
+        self.record_inherited_origin();
+    }
+
+    #[doc="
+        Record, for each inherited property, whether the just-completed match set
+        it explicitly. Must be called immediately after matching and before the
+        inherited cascade runs.
+    "]
+    fn record_inherited_origin() {
+        let layout_data = self.aux(|x| copy x);
+        layout_data.inherited_explicit.text_color =
+            layout_data.specified_style.text_color.is_some();
+        layout_data.inherited_explicit.font_size =
+            layout_data.specified_style.font_size.is_some();
+    }
+
+    #[doc="
+        Resolve this node's inherited properties against its parent's computed
+        style. Only `text_color` and `font_size` are inherited; a property the
+        node did not set explicitly takes the parent's value. The reset
+        properties (`background_color`, `width`, `height`, `display_type`) are
+        left untouched so they keep their matched or default value.
+
+        Unlike a one-shot fill, this overwrites the inherited properties every
+        time, so when an ancestor's inherited value changes on an incremental
+        restyle the descendant re-inherits even though it was not re-matched.
+
+        Interaction with animations: this clobbers any inherited property whose
+        `inherited_explicit` flag is false. The animation pass
+        (`update_style_for_animation`) therefore sets that flag when it animates
+        an inherited property, and is contracted to run strictly after each
+        `recompute_style_for_subtree` so its samples are not overwritten here.
+    "]
+    fn cascade_inherited(parent: &SpecifiedStyle) {
+        let layout_data = self.aux(|x| copy x);
+        let style = layout_data.specified_style;
+        if !layout_data.inherited_explicit.text_color {
+            style.text_color = copy parent.text_color;
+        }
+        if !layout_data.inherited_explicit.font_size {
+            style.font_size = copy parent.font_size;
+        }
+    }
+
+    #[doc="Read this node's restyle hint, or `RESTYLE_NONE` if it has no layout
+           data yet."]
+    fn restyle_hint() -> RestyleHint {
+        if self.has_aux() { self.aux(|x| copy x).restyle_hint } else { RESTYLE_NONE }
+    }
+
+    #[doc="Union `hint` into this node's restyle hint."]
+    fn set_restyle_hint(hint: RestyleHint) {
+        let layout_data = self.aux(|x| copy x);
+        layout_data.restyle_hint |= hint;
+    }
+
+    #[doc="Clear this node's restyle hint once it has been restyled."]
+    fn clear_restyle_hint() {
+        let layout_data = self.aux(|x| copy x);
+        layout_data.restyle_hint = RESTYLE_NONE;
+    }
+
+    #[doc="This node's parent in the layout tree, or `None` at the root."]
+    fn tree_parent() -> Option<Node> {
+        do NTree.with_tree_fields(self) |tf| { tf.parent }
+    }
+
+    #[doc="Whether any ancestor of this node is in the `dirty` set, keyed by
+           layout-data identity."]
+    fn has_dirty_ancestor(dirty: HashMap<uint, ()>) -> bool {
+        let mut ancestor = self.tree_parent();
+        loop {
+            match ancestor {
+              Some(parent) => {
+                if dirty.contains_key(parent.layout_data_key()) { return true; }
+                ancestor = parent.tree_parent();
+              }
+              None => return false
+            }
+        }
+    }
+
+    #[doc="
+        Collect into a flat work list the nodes that actually need rematching,
+        each paired with the identity of its parent's layout data so a worker
+        can form its `SharingKey`. A node is included when `force` is set (an
+        ancestor requested `RESTYLE_DESCENDANTS`) or its own hint carries
+        `RESTYLE_SELF`. Children are forced whenever this node carries
+        `RESTYLE_DESCENDANTS`.
+    "]
+    fn flatten_dirty(force: bool, parent_key: uint) -> ~[StyleWorkItem] {
+        let hint = self.restyle_hint();
+        let restyle_self = force || (hint & RESTYLE_SELF) != RESTYLE_NONE;
+        let force_children = force || (hint & RESTYLE_DESCENDANTS) != RESTYLE_NONE;
+
+        let mut items = if restyle_self {
+            ~[{node : self, parent_key : parent_key}]
+        } else {
+            ~[]
+        };
+
+        // Only descend when the children are forced, or a dirty descendant was
+        // summarized below us; an entirely clean subtree is pruned here.
+        let descend = force_children
+                      || (hint & RESTYLE_DIRTY_DESCENDANT) != RESTYLE_NONE;
+        if descend {
+            let key = self.layout_data_key();
+            for NTree.each_child(self) |kid| {
+                items += kid.flatten_dirty(force_children, key);
+            }
+        }
+
+        // This node has now been scheduled or found clean; drop its hints
+        // (including the summary bit) so the next restyle starts fresh.
+        self.clear_restyle_hint();
+        items
+    }
+
+    #[doc="
+        Apply the inherited half of the cascade over the subtree in a top-down
+        walk. Because a child inherits from its parent, this must run after all
+        nodes have been matched and strictly parent-before-child; it only copies
+        a couple of fields, so it is cheap enough to do sequentially.
+    "]
+    fn cascade_inherited_subtree(parent: Option<SpecifiedStyle>) {
+        match parent {
+          Some(ref parent_style) => self.cascade_inherited(parent_style),
+          None => ()
+        }
+
+        let resolved = self.get_specified_style();
+        for NTree.each_child(self) |kid| {
+            kid.cascade_inherited_subtree(Some(copy resolved));
+        }
+    }
 }
 
 trait StyleMethods {
     fn initialize_style_for_subtree() -> ~[@LayoutData];
     fn get_specified_style() -> SpecifiedStyle;
-    fn recompute_style_for_subtree(styles : ARC<Stylesheet>);
+    fn mark_dirty(hint : RestyleHint);
+    fn mark_dirty_for_added_rules(additions : ARC<Stylist>);
+    fn recompute_style_for_subtree(stylist : ARC<Stylist>);
 }
 
 impl Node : StyleMethods {
@@ -141,34 +750,435 @@ impl Node : StyleMethods {
         return copy *self.aux(|x| copy x).specified_style;
     }
 
+    #[doc="
+        Mark this node dirty. Pass `RESTYLE_SELF` when only the node's own style
+        may have changed (e.g. one of its attributes changed), or additionally
+        `RESTYLE_DESCENDANTS` to force its whole subtree to be revisited.
+    "]
+    fn mark_dirty(hint : RestyleHint) {
+        self.set_restyle_hint(hint);
+
+        // Summarize upward so every ancestor records that its subtree contains
+        // a dirty node. `flatten_dirty` follows this trail down to the dirt and
+        // prunes everything else. Stop as soon as an ancestor is already
+        // summarized, since the trail above it is then already in place.
+        let mut ancestor = self.tree_parent();
+        loop {
+            match ancestor {
+              Some(node) => {
+                if (node.restyle_hint() & RESTYLE_DIRTY_DESCENDANT) != RESTYLE_NONE {
+                    break;
+                }
+                node.set_restyle_hint(RESTYLE_DIRTY_DESCENDANT);
+                ancestor = node.tree_parent();
+              }
+              None => break
+            }
+        }
+    }
+
+    #[doc="
+        Dirty only the nodes in this subtree that a set of newly added rules
+        could match, leaving everything else clean. This lets an incremental
+        stylesheet addition avoid the global rematch that a wholesale stylesheet
+        replacement would require.
+    "]
+    fn mark_dirty_for_added_rules(additions : ARC<Stylist>) {
+        // `matched_rules` also returns the universal bucket, so a node is
+        // dirtied either because an added rule targets it specifically or
+        // because an added universal rule matches everything.
+        if self.matched_rules(clone(&additions)).len() > 0u {
+            self.mark_dirty(RESTYLE_SELF);
+        }
+        for NTree.each_child(self) |kid| {
+            kid.mark_dirty_for_added_rules(clone(&additions));
+        }
+    }
+
     #[doc="
         Performs CSS selector matching on a subtree.
 
         This is, importantly, the function that updates the layout data for the node (the reader-
         auxiliary box in the RCU model) with the computed style.
     "]
-    fn recompute_style_for_subtree(styles : ARC<Stylesheet>) {
-        listen(|ack_chan| {
-            let mut i = 0u;
-            
-            // Compute the styles of each of our children in parallel
-            for NTree.each_child(self) |kid| {
-                i = i + 1u;
-                let new_styles = clone(&styles);
-                
-                task::spawn(|| {
-                    kid.recompute_style_for_subtree(new_styles); 
+    fn recompute_style_for_subtree(stylist : ARC<Stylist>) {
+        // Phase 1: flatten the dirty nodes into a work list. Selector matching
+        // of one node never mutates another node's `LayoutData`, so the nodes
+        // can be matched in any order; only the parent-before-child inheritance
+        // pass below cares about ordering. Clean subtrees are skipped here. The
+        // root of the subtree has no parent within it, so it gets the sentinel
+        // parent key 0.
+        let work = self.flatten_dirty(false, 0u);
+
+        // Phase 2: dispatch fixed-size chunks to a bounded pool of workers,
+        // each with its own style-sharing cache, and join on a single barrier.
+        // This keeps the parallel win while avoiding a task and ack per node.
+        let num_workers = 8u;
+        let chunk_size = uint::max(1u, (work.len() + num_workers - 1u) / num_workers);
+
+        do listen |ack_chan| {
+            let mut dispatched = 0u;
+            let mut start = 0u;
+            while start < work.len() {
+                let end = uint::min(start + chunk_size, work.len());
+                let chunk = vec::slice(work, start, end);
+                let chunk_stylist = clone(&stylist);
+                dispatched += 1u;
+
+                do task::spawn {
+                    // One cache shared across the whole chunk so inserts made
+                    // for one node survive to the next, giving sibling runs a
+                    // non-zero hit rate. Sharing is disabled wholesale when the
+                    // stylesheet has sibling selectors the key cannot model.
+                    let allow_sharing = !get(&chunk_stylist).has_sibling_rules;
+                    let cache = empty_style_sharing_cache(16u);
+                    for chunk.each |item| {
+                        item.node.match_css_style_shared(
+                            item.node.matched_rules(clone(&chunk_stylist)),
+                            item.parent_key,
+                            allow_sharing,
+                            cache);
+                    }
                     ack_chan.send(());
-                })
+                }
+
+                start = end;
             }
 
-            self.match_css_style(*get(&styles));
-            
-            // Make sure we have finished updating the tree before returning
-            while i > 0 {
+            while dispatched > 0u {
                 ack_chan.recv();
-                i = i - 1u;
+                dispatched -= 1u;
+            }
+        }
+
+        // `flatten_dirty` has already cleared the hint of every node it visited,
+        // so the restyled nodes are clean until something dirties them again.
+
+        // Phase 3: resolve inherited properties top-down now that every dirty
+        // node has a matched style. Rather than walk the whole tree every time,
+        // cascade only from the topmost dirtied nodes (those with no dirtied
+        // ancestor). Their subtrees are disjoint and together cover every node
+        // whose inherited context may have changed; when nothing is dirty this
+        // does no work, and when the whole tree is dirty it collapses to the
+        // single root walk.
+        if work.len() > 0u {
+            let dirty = HashMap();
+            for work.each |item| { dirty.insert(item.node.layout_data_key(), ()); }
+
+            for work.each |item| {
+                if !item.node.has_dirty_ancestor(dirty) {
+                    let parent_style = match item.node.tree_parent() {
+                      Some(parent) => Some(parent.get_specified_style()),
+                      None => None
+                    };
+                    item.node.cascade_inherited_subtree(parent_style);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * The animatable fields of a `SpecifiedStyle`. Numeric (`Unit`) and `Color`
+ * properties interpolate smoothly; `display_type` and other discrete
+ * properties flip at the midpoint of the animation.
+ */
+enum AnimatedProperty {
+    AnimateWidth,
+    AnimateHeight,
+    AnimateFontSize,
+    AnimateTextColor,
+    AnimateBackgroundColor,
+    AnimateDisplayType
+}
+
+/// A value an animation moves between. Numeric properties carry a `Unit`,
+/// colors a `Color`, and discrete properties a `DisplayType`.
+enum AnimationValue {
+    UnitValue(Unit),
+    ColorValue(Color),
+    DisplayValue(DisplayType)
+}
+
+/// The easing applied to an animation's progress. `Linear` samples progress
+/// directly; `EaseInOut` smooths the endpoints with a cubic step.
+enum TimingFunction {
+    Linear,
+    EaseInOut
+}
+
+/**
+ * A single time-varying style change: which property of which node is moving
+ * from `from_value` to `to_value`, starting at `start_time` and lasting
+ * `duration` (both in seconds), shaped by `timing`.
+ */
+type Animation = {
+    property : AnimatedProperty,
+    from_value : AnimationValue,
+    to_value : AnimationValue,
+    start_time : float,
+    duration : float,
+    timing : TimingFunction
+};
+
+/// An animation together with the node it drives. The maps are keyed by the
+/// address of the node's layout data, matching the per-layout keying the rest
+/// of the style system uses.
+type RunningAnimation = {
+    target : Node,
+    animation : Animation
+};
+
+/**
+ * Holds the animations running on a layout, split into those still sampling and
+ * those that have finished. The layout task ticks this each frame after
+ * recomputing style, and finished animations move to `expired` so they stop
+ * being sampled.
+ */
+type AnimationManager = {
+    running : HashMap<uint, ~[RunningAnimation]>,
+    expired : HashMap<uint, ~[RunningAnimation]>
+};
+
+/// Create an empty animation manager.
+fn empty_animation_manager() -> AnimationManager {
+    {running : HashMap(), expired : HashMap()}
+}
+
+/// Linearly interpolate between two floats.
+pure fn lerp_float(from: float, to: float, t: float) -> float {
+    from + (to - from) * t
+}
+
+/// Interpolate two `Unit` values of the same kind. Mismatched or non-numeric
+/// units are discrete, so they flip at the midpoint rather than blend.
+pure fn lerp_unit(from: Unit, to: Unit, t: float) -> Unit {
+    match (from, to) {
+      (Px(a), Px(b)) => Px(lerp_float(a, b, t)),
+      (Em(a), Em(b)) => Em(lerp_float(a, b, t)),
+      (Pt(a), Pt(b)) => Pt(lerp_float(a, b, t)),
+      (Percent(a), Percent(b)) => Percent(lerp_float(a, b, t)),
+      _ => if t < 0.5 { from } else { to }
+    }
+}
+
+/// Interpolate two colors channel-by-channel.
+pure fn lerp_color(from: Color, to: Color, t: float) -> Color {
+    rgb(lerp_float(from.red as float, to.red as float, t) as u8,
+        lerp_float(from.green as float, to.green as float, t) as u8,
+        lerp_float(from.blue as float, to.blue as float, t) as u8)
+}
+
+/// Apply the timing function to a raw progress value in `[0, 1]`.
+pure fn apply_timing(timing: TimingFunction, t: float) -> float {
+    match timing {
+      Linear => t,
+      // Smoothstep: 3t^2 - 2t^3, which eases both ends.
+      EaseInOut => t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Node {
+    /// The address of this node's layout data, used to key the animation maps.
+    fn layout_data_key() -> uint {
+        ptr::to_uint(ptr::addr_of(&*self.aux(|x| copy x)))
+    }
+
+    /// Overwrite a single animated field of this node's specified style with a
+    /// freshly sampled value.
+    ///
+    /// Animating an inherited property (`text_color`, `font_size`) also marks it
+    /// as explicit, so a subsequent `cascade_inherited` treats the animated
+    /// value like a matched one and does not replace it with the parent's. This
+    /// protects the sample only from a cascade that does not re-match the node:
+    /// re-matching overwrites the style and recomputes the flag, so a sample
+    /// taken before `recompute_style_for_subtree` is lost for re-matched nodes.
+    fn set_animated_property(property: AnimatedProperty, value: AnimationValue) {
+        let layout_data = self.aux(|x| copy x);
+        let style = layout_data.specified_style;
+        match (property, value) {
+          (AnimateWidth, UnitValue(u)) => style.width = Some(u),
+          (AnimateHeight, UnitValue(u)) => style.height = Some(u),
+          (AnimateFontSize, UnitValue(u)) => {
+            style.font_size = Some(u);
+            layout_data.inherited_explicit.font_size = true;
+          }
+          (AnimateTextColor, ColorValue(c)) => {
+            style.text_color = Some(c);
+            layout_data.inherited_explicit.text_color = true;
+          }
+          (AnimateBackgroundColor, ColorValue(c)) => style.background_color = Some(c),
+          (AnimateDisplayType, DisplayValue(d)) => style.display_type = Some(d),
+          // A value whose kind does not match its property is ignored.
+          _ => ()
+        }
+    }
+}
+
+impl AnimationManager {
+    /// Register a running animation on `target`.
+    fn add(target: Node, animation: Animation) {
+        let key = target.layout_data_key();
+        let entry = {target : target, animation : animation};
+        match self.running.find(key) {
+          Some(existing) => { self.running.insert(key, existing + ~[entry]); }
+          None => { self.running.insert(key, ~[entry]); }
+        }
+    }
+
+    /// File a finished animation under the expired map so it stops sampling.
+    fn expire(key: uint, entry: RunningAnimation) {
+        match self.expired.find(key) {
+          Some(existing) => { self.expired.insert(key, existing + ~[entry]); }
+          None => { self.expired.insert(key, ~[entry]); }
+        }
+    }
+
+    #[doc="
+        Tick every running animation to time `now`, overwriting the sampled
+        fields of each target's `SpecifiedStyle`. This is the entry point the
+        layout task calls each frame, after `recompute_style_for_subtree` has
+        established the matched styles. Animations whose end time has passed are
+        sampled at their final value once and moved to the expired set.
+    "]
+    fn update_style_for_animation(now: float) {
+        let keys = self.running.keys();
+        for keys.each |key| {
+            let entries = self.running.get(*key);
+            let mut still_running = ~[];
+
+            for entries.each |entry| {
+                let animation = copy entry.animation;
+                let elapsed = now - animation.start_time;
+                let finished = elapsed >= animation.duration;
+
+                // Clamp progress into [0, 1] and shape it with the timing curve.
+                let raw = if animation.duration <= 0.0 {
+                    1.0
+                } else if elapsed <= 0.0 {
+                    0.0
+                } else if finished {
+                    1.0
+                } else {
+                    elapsed / animation.duration
+                };
+                let t = apply_timing(animation.timing, raw);
+
+                entry.target.set_animated_property(animation.property,
+                                                   sample_animation(&animation, t));
+
+                if finished {
+                    self.expire(*key, copy *entry);
+                } else {
+                    still_running += ~[copy *entry];
+                }
             }
-        })
+
+            if still_running.len() > 0u {
+                self.running.insert(*key, still_running);
+            } else {
+                self.running.remove(*key);
+            }
+        }
+    }
+}
+
+/// Sample an animation at progress `t`, producing the value to write into the
+/// target's specified style. Numeric and color values blend; discrete values
+/// flip at the midpoint.
+fn sample_animation(animation: &Animation, t: float) -> AnimationValue {
+    match (copy animation.from_value, copy animation.to_value) {
+      (UnitValue(from), UnitValue(to)) => UnitValue(lerp_unit(from, to, t)),
+      (ColorValue(from), ColorValue(to)) => ColorValue(lerp_color(from, to, t)),
+      (from, to) => if t < 0.5 { from } else { to }
+    }
+}
+
+#[cfg(test)]
+fn test_dummy_style() -> SpecifiedStyle {
+    {mut background_color : None,
+     mut display_type : None,
+     mut font_size : None,
+     mut height : None,
+     mut text_color : None,
+     mut width : None}
+}
+
+#[cfg(test)]
+fn test_key(element_kind: uint, parent: uint) -> SharingKey {
+    {element_kind : element_kind, classes : ~[], parent : parent}
+}
+
+#[test]
+fn test_lerp_unit_numeric() {
+    match lerp_unit(Px(0.0), Px(10.0), 0.5) {
+      Px(v) => assert v == 5.0,
+      _ => fail ~"expected an interpolated Px"
+    }
+    match lerp_unit(Percent(20.0), Percent(40.0), 0.25) {
+      Percent(v) => assert v == 25.0,
+      _ => fail ~"expected an interpolated Percent"
+    }
+}
+
+#[test]
+fn test_lerp_unit_discrete_flips_at_midpoint() {
+    // Mismatched unit kinds cannot blend, so they flip at the midpoint.
+    match lerp_unit(Px(0.0), Em(10.0), 0.25) {
+      Px(v) => assert v == 0.0,
+      _ => fail ~"expected the from-value before the midpoint"
     }
-}
\ No newline at end of file
+    match lerp_unit(Px(0.0), Em(10.0), 0.75) {
+      Em(v) => assert v == 10.0,
+      _ => fail ~"expected the to-value after the midpoint"
+    }
+}
+
+#[test]
+fn test_lerp_color_channels() {
+    let c = lerp_color(rgb(0u8, 0u8, 0u8), rgb(10u8, 20u8, 30u8), 0.5);
+    assert c.red == 5u8;
+    assert c.green == 10u8;
+    assert c.blue == 15u8;
+}
+
+#[test]
+fn test_apply_timing() {
+    assert apply_timing(Linear, 0.3) == 0.3;
+    // Smoothstep pins the endpoints and is symmetric about the midpoint.
+    assert apply_timing(EaseInOut, 0.0) == 0.0;
+    assert apply_timing(EaseInOut, 1.0) == 1.0;
+    assert apply_timing(EaseInOut, 0.5) == 0.5;
+}
+
+#[test]
+fn test_cache_evicts_least_recently_used() {
+    let cache = empty_style_sharing_cache(2u);
+    let k1 = test_key(1u, 0u);
+    let k2 = test_key(2u, 0u);
+    let k3 = test_key(3u, 0u);
+
+    cache.insert(copy k1, test_dummy_style());
+    cache.insert(copy k2, test_dummy_style());
+
+    // Touching k1 makes k2 the least-recently-used entry.
+    assert cache.find(&k1).is_some();
+
+    // A third insert overflows the cache and evicts the LRU entry, k2.
+    cache.insert(copy k3, test_dummy_style());
+    assert cache.find(&k2).is_none();
+    assert cache.find(&k1).is_some();
+    assert cache.find(&k3).is_some();
+}
+
+#[test]
+fn test_cache_does_not_share_across_parents() {
+    // Same element kind and classes but different parents must not share.
+    let cache = empty_style_sharing_cache(4u);
+    let stored = test_key(1u, 100u);
+    let other_parent = test_key(1u, 200u);
+
+    cache.insert(copy stored, test_dummy_style());
+    assert cache.find(&other_parent).is_none();
+    assert cache.find(&stored).is_some();
+}